@@ -1,7 +1,183 @@
-use std::{fs, path::{Path, PathBuf}, io, os::unix::fs::MetadataExt};
+use std::{fs, collections::BTreeMap, cell::RefCell, path::{Path, PathBuf}, io, os::unix::fs::MetadataExt};
 use dirs::config_dir;
 use serde::{Deserialize, Serialize};
 
+// The subset of filesystem primitives the helpers in this crate need. Going
+// through a trait lets the free functions run over the real disk in production
+// and over an in-memory `FakeFs` in tests without touching the real tree.
+pub trait Fs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error>;
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error>;
+    fn metadata(&self, path: &Path) -> Result<Meta, io::Error>;
+    fn copy(&self, from: &Path, to: &Path) -> Result<u64, io::Error>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error>;
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error>;
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), io::Error>;
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error>;
+}
+
+// The fields of `fs::Metadata` the helpers actually read, flattened so a fake
+// filesystem can synthesize them.
+pub struct Meta {
+    pub is_dir: bool,
+    pub len: u64,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+// `Fs` backed by `std::fs`.
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path)? {
+            paths.push(entry?.path());
+        }
+        Ok(paths)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+        fs::read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Meta, io::Error> {
+        let metadata = fs::metadata(path)?;
+        Ok(Meta {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<u64, io::Error> {
+        fs::copy(from, to)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+        fs::remove_file(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+        fs::write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        fs::create_dir_all(path)
+    }
+}
+
+// In-memory `Fs` for tests. Directories are implied by the paths of the files
+// they contain; each file is handed a stable synthetic `ino` so the dev/ino
+// identity check in `eq_files` behaves. Set `exdev_on_rename` to exercise the
+// cross-filesystem fallback in `rename_file`.
+pub struct FakeFs {
+    files: RefCell<BTreeMap<PathBuf, Vec<u8>>>,
+    inos: RefCell<BTreeMap<PathBuf, u64>>,
+    next_ino: RefCell<u64>,
+    pub exdev_on_rename: bool,
+}
+
+impl FakeFs {
+    pub fn new() -> Self {
+        Self {
+            files: RefCell::new(BTreeMap::new()),
+            inos: RefCell::new(BTreeMap::new()),
+            next_ino: RefCell::new(1),
+            exdev_on_rename: false,
+        }
+    }
+
+    fn ino_for(&self, path: &Path) -> u64 {
+        let mut inos = self.inos.borrow_mut();
+        if let Some(ino) = inos.get(path) {
+            return *ino;
+        }
+        let mut next = self.next_ino.borrow_mut();
+        let ino = *next;
+        *next += 1;
+        inos.insert(path.to_path_buf(), ino);
+        ino
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.borrow().keys().any(|p| p != path && p.starts_with(path))
+    }
+}
+
+impl Default for FakeFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fs for FakeFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        let files = self.files.borrow();
+        let mut entries = std::collections::BTreeSet::new();
+        for stored in files.keys() {
+            if let Ok(rest) = stored.strip_prefix(path) {
+                if let Some(first) = rest.components().next() {
+                    entries.insert(path.join(first.as_os_str()));
+                }
+            }
+        }
+        if entries.is_empty() && !self.is_dir(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such directory: {}", path.display())));
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+        self.files.borrow().get(path).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", path.display())))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Meta, io::Error> {
+        if let Some(contents) = self.files.borrow().get(path) {
+            return Ok(Meta { is_dir: false, len: contents.len() as u64, dev: 1, ino: self.ino_for(path) });
+        }
+        if self.is_dir(path) {
+            return Ok(Meta { is_dir: true, len: 0, dev: 1, ino: self.ino_for(path) });
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", path.display())))
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<u64, io::Error> {
+        let contents = self.files.borrow().get(from).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", from.display())))?;
+        let len = contents.len() as u64;
+        self.files.borrow_mut().insert(to.to_path_buf(), contents);
+        Ok(len)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        if self.exdev_on_rename {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        let contents = self.files.borrow_mut().remove(from).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", from.display())))?;
+        self.files.borrow_mut().insert(to.to_path_buf(), contents);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+        self.files.borrow_mut().remove(path).map(|_| ()).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No such file: {}", path.display())))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+        self.files.borrow_mut().insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<(), io::Error> {
+        // Directories are implicit in `FakeFs`, so there is nothing to create.
+        Ok(())
+    }
+}
+
 pub struct Project {
     pub project_name: String,
     pub config_dir: PathBuf,
@@ -19,57 +195,97 @@ impl Project {
     }
 
     pub fn config<Config: Deserialize<'static> + Serialize + Default>(&self) -> Config {
+        match self.try_config() {
+            Ok(config) => config,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Fallible counterpart to `config`: writes the default `config.toml` on
+    // first use, then layers `config.local.toml` (optional, per-machine) and
+    // environment variables prefixed with the uppercased project name over it,
+    // returning a typed error for each failure instead of panicking.
+    pub fn try_config<Config: Deserialize<'static> + Serialize + Default>(&self) -> Result<Config, ConfigError> {
         let config_path = self.config_dir.join("config.toml");
-        fs::create_dir_all(&self.config_dir).expect("Failed to create config directory");
+        fs::create_dir_all(&self.config_dir).map_err(ConfigError::CreateDir)?;
 
         if !config_path.exists() {
             let default_config = Config::default();
-            let toml = match toml::to_string_pretty(&default_config) {
-                Ok(toml) => toml,
-                Err(err) => panic!("Failed to serialize default config: {}", err),
-            };
-            match fs::write(&config_path, toml) {
-                Ok(_) => (),
-                Err(err) => panic!("Failed to write default config: {}", err),
-            }
+            let toml = toml::to_string_pretty(&default_config).map_err(ConfigError::Serialize)?;
+            atomic_write(&config_path, toml).map_err(ConfigError::Write)?;
             log::debug!("Default config written to {}", config_path.display());
         }
 
-        let config = match config::Config::builder().add_source(config::File::from(config_path.as_path())).build() {
-            Ok(config) => config,
-            Err(err) => panic!("Failed to build config: {}", err),
-        };
-        let config = match config.try_deserialize::<Config>() {
-            Ok(config) => config,
-            Err(err) => panic!("Failed to deserialize config: {}", err),
-        };
+        let local_path = self.config_dir.join("config.local.toml");
+        let config = config::Config::builder()
+            .add_source(config::File::from(config_path.as_path()))
+            .add_source(config::File::from(local_path.as_path()).required(false))
+            .add_source(config::Environment::with_prefix(&self.project_name.to_uppercase()))
+            .build()
+            .map_err(ConfigError::Build)?;
+        config.try_deserialize::<Config>().map_err(ConfigError::Deserialize)
+    }
+}
+
+// Failure modes of `Project::try_config`, one variant per step.
+#[derive(Debug)]
+pub enum ConfigError {
+    CreateDir(io::Error),
+    Serialize(toml::ser::Error),
+    Write(io::Error),
+    Build(config::ConfigError),
+    Deserialize(config::ConfigError),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::CreateDir(err) => write!(f, "Failed to create config directory: {}", err),
+            ConfigError::Serialize(err) => write!(f, "Failed to serialize default config: {}", err),
+            ConfigError::Write(err) => write!(f, "Failed to write default config: {}", err),
+            ConfigError::Build(err) => write!(f, "Failed to build config: {}", err),
+            ConfigError::Deserialize(err) => write!(f, "Failed to deserialize config: {}", err),
+        }
+    }
+}
 
-        config
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::CreateDir(err) => Some(err),
+            ConfigError::Serialize(err) => Some(err),
+            ConfigError::Write(err) => Some(err),
+            ConfigError::Build(err) => Some(err),
+            ConfigError::Deserialize(err) => Some(err),
+        }
     }
 }
 
-pub fn walk_dir<R>(dir: impl AsRef<Path>, mut f: impl FnMut(PathBuf) -> R) -> Vec<R> {
+pub fn walk_dir<R>(dir: impl AsRef<Path>, f: impl FnMut(PathBuf) -> R) -> Vec<R> {
+    walk_dir_in(&RealFs, dir, f)
+}
+
+pub fn walk_dir_in<R>(fs: &dyn Fs, dir: impl AsRef<Path>, mut f: impl FnMut(PathBuf) -> R) -> Vec<R> {
     // must be implement no recursive
     let mut dir_stack = vec![dir.as_ref().to_path_buf()];
     let mut results = Vec::new();
     while let Some(dir) = dir_stack.pop() {
-        let iter = match fs::read_dir(&dir) {
-            Ok(iter) => iter,
+        let paths = match fs.read_dir(&dir) {
+            Ok(paths) => paths,
             Err(err) => {
                 log::warn!("Ignoring error {} in {}", err, dir.display());
                 continue;
             }
         };
-        for entry in iter {
-            let entry = match entry {
-                Ok(entry) => entry,
+        for path in paths {
+            let is_dir = match fs.metadata(&path) {
+                Ok(metadata) => metadata.is_dir,
                 Err(err) => {
-                    log::warn!("Ignoring error {} in {}", err, dir.display());
+                    log::warn!("Ignoring error {} in {}", err, path.display());
                     continue;
                 }
             };
-            let path = entry.path();
-            if path.is_dir() {
+            if is_dir {
                 dir_stack.push(path);
             } else {
                 results.push(f(path));
@@ -79,6 +295,117 @@ pub fn walk_dir<R>(dir: impl AsRef<Path>, mut f: impl FnMut(PathBuf) -> R) -> Ve
     results
 }
 
+// A single compiled `.gitignore`/glob rule, remembering the directory it was
+// declared in so relative matches are resolved against the right base.
+#[derive(Clone)]
+struct IgnoreRule {
+    base: PathBuf,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+    pattern: glob::Pattern,
+}
+
+// Compile one `.gitignore`/glob line into a rule, returning `None` for blank
+// lines, comments and patterns that do not parse.
+fn compile_rule(base: &Path, line: &str) -> Option<IgnoreRule> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, rest) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (dir_only, rest) = match rest.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let anchored = rest.contains('/');
+    let rest = rest.strip_prefix('/').unwrap_or(rest);
+    let pattern = glob::Pattern::new(rest).ok()?;
+    Some(IgnoreRule { base: base.to_path_buf(), negate, dir_only, anchored, pattern })
+}
+
+// Decide whether `path` is ignored by walking the accumulated rules in order:
+// the last rule that matches wins, and a `!`-prefixed rule re-includes.
+fn is_ignored(path: &Path, is_dir: bool, rules: &[IgnoreRule]) -> bool {
+    let mut ignored = false;
+    for rule in rules {
+        if rule.dir_only && !is_dir {
+            continue;
+        }
+        let rel = match path.strip_prefix(&rule.base) {
+            Ok(rel) => rel,
+            Err(_) => continue,
+        };
+        let matched = if rule.anchored {
+            rule.pattern.matches_path(rel)
+        } else {
+            rel.file_name().map(|name| rule.pattern.matches(&name.to_string_lossy())).unwrap_or(false)
+        };
+        if matched {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+// Like `walk_dir`, but prunes paths matched by any `.gitignore` encountered
+// along the way and by the caller-supplied `patterns` (same syntax, evaluated
+// first so they act as a baseline include/exclude set). Ignored directories
+// are never descended into, so large trees are pruned cheaply.
+pub fn walk_dir_filtered<R>(dir: impl AsRef<Path>, patterns: impl IntoIterator<Item = impl AsRef<str>>, f: impl FnMut(PathBuf) -> R) -> Vec<R> {
+    walk_dir_filtered_in(&RealFs, dir, patterns, f)
+}
+
+pub fn walk_dir_filtered_in<R>(fs: &dyn Fs, dir: impl AsRef<Path>, patterns: impl IntoIterator<Item = impl AsRef<str>>, mut f: impl FnMut(PathBuf) -> R) -> Vec<R> {
+    // must be implement no recursive, mirroring `walk_dir_in`
+    let dir = dir.as_ref().to_path_buf();
+    let mut base_rules = Vec::new();
+    for pattern in patterns {
+        if let Some(rule) = compile_rule(&dir, pattern.as_ref()) {
+            base_rules.push(rule);
+        }
+    }
+    let mut dir_stack = vec![(dir.clone(), base_rules)];
+    let mut results = Vec::new();
+    while let Some((current, mut rules)) = dir_stack.pop() {
+        if let Ok(bytes) = fs.read(&current.join(".gitignore")) {
+            for line in String::from_utf8_lossy(&bytes).lines() {
+                if let Some(rule) = compile_rule(&current, line) {
+                    rules.push(rule);
+                }
+            }
+        }
+        let paths = match fs.read_dir(&current) {
+            Ok(paths) => paths,
+            Err(err) => {
+                log::warn!("Ignoring error {} in {}", err, current.display());
+                continue;
+            }
+        };
+        for path in paths {
+            let is_dir = match fs.metadata(&path) {
+                Ok(metadata) => metadata.is_dir,
+                Err(err) => {
+                    log::warn!("Ignoring error {} in {}", err, path.display());
+                    continue;
+                }
+            };
+            if is_ignored(&path, is_dir, &rules) {
+                continue;
+            }
+            if is_dir {
+                dir_stack.push((path, rules.clone()));
+            } else {
+                results.push(f(path));
+            }
+        }
+    }
+    results
+}
+
 pub fn project(project_name: impl Into<String>) -> Project {
     Project::new(project_name)
 }
@@ -91,15 +418,19 @@ pub fn almost_eq<F: num_traits::Float>(a: F, b: F, relative_tolerance: F) -> boo
 
 // allow to rename file across different filesystems
 pub fn rename_file(from_path: impl AsRef<Path>, to_path: impl AsRef<Path>) -> Result<(), io::Error> {
+    rename_file_in(&RealFs, from_path, to_path)
+}
+
+pub fn rename_file_in(fs: &dyn Fs, from_path: impl AsRef<Path>, to_path: impl AsRef<Path>) -> Result<(), io::Error> {
     let from_path = from_path.as_ref();
     let to_path = to_path.as_ref();
-    match fs::rename(from_path, to_path) {
+    match fs.rename(from_path, to_path) {
         Ok(_) => Ok(()),
         Err(e) => {
             match e.raw_os_error() {
                 Some(libc::EXDEV) => {
-                    fs::copy(from_path, to_path)?;
-                    fs::remove_file(from_path)?;
+                    fs.copy(from_path, to_path)?;
+                    fs.remove_file(from_path)?;
                     Ok(())
                 },
                 _ => Err(e),
@@ -108,18 +439,129 @@ pub fn rename_file(from_path: impl AsRef<Path>, to_path: impl AsRef<Path>) -> Re
     }
 }
 
+// write contents without ever leaving a half-written file behind: write to a
+// sibling temp file in the same directory, fsync it, then rename over the
+// destination. falls back to the same copy-and-remove path as `rename_file`
+// when the rename crosses a filesystem boundary.
+pub fn atomic_write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<(), io::Error> {
+    use std::io::Write;
+    let path = path.as_ref();
+    let contents = contents.as_ref();
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let mut tmp_filename = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid path: {}", path.display())))?.to_os_string();
+    tmp_filename.push(format!(".tmp.{}.{}", std::process::id(), temp_suffix()));
+    let tmp_path = path.with_file_name(tmp_filename);
+
+    let write_result = (|| -> Result<(), io::Error> {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        Ok(())
+    })();
+    if let Err(err) = write_result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    match fs::rename(&tmp_path, path) {
+        Ok(_) => sync_parent_dir(path),
+        Err(e) => match e.raw_os_error() {
+            Some(libc::EXDEV) => {
+                let result = fs::copy(&tmp_path, path).and_then(|_| fs::remove_file(&tmp_path));
+                if result.is_err() {
+                    let _ = fs::remove_file(&tmp_path);
+                    return result.map(|_| ());
+                }
+                sync_parent_dir(path)
+            },
+            _ => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            },
+        }
+    }
+}
+
+// fsync the directory so the rename that published the new file is itself
+// durable, not just the file contents.
+fn sync_parent_dir(path: &Path) -> Result<(), io::Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(parent)?.sync_all()
+}
+
+// best-effort unique suffix for the atomic-write temp file; the rename is the
+// real guarantee, this only keeps concurrent writers from colliding.
+fn temp_suffix() -> u128 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
 pub fn eq_files(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool, io::Error> {
+    eq_files_in(&RealFs, a, b)
+}
+
+pub fn eq_files_in(fs: &dyn Fs, a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool, io::Error> {
+    let a = a.as_ref();
+    let b = b.as_ref();
+    let a_metadata = metadata_if_exists_in(fs, a)?;
+    let b_metadata = metadata_if_exists_in(fs, b)?;
+    match (a_metadata, b_metadata) {
+        (Some(a_metadata), Some(b_metadata)) => Ok(a_metadata.dev == b_metadata.dev && a_metadata.ino == b_metadata.ino),
+        (None, None) => Err(io::Error::new(io::ErrorKind::NotFound, "Both files do not exist")),
+        _ => Ok(false),
+    }
+}
+
+// Like `eq_files`, but treats byte-identical copies as equal even when they
+// are not the same inode: short-circuits on the dev/ino check, then on a size
+// mismatch, and only streams both files through SHA-256 when those are
+// inconclusive so whole files are never held in memory.
+pub fn eq_files_by_content(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<bool, io::Error> {
     let a = a.as_ref();
     let b = b.as_ref();
+    if eq_files(a, b)? {
+        return Ok(true);
+    }
     let a_metadata = metadata_if_exists(a)?;
     let b_metadata = metadata_if_exists(b)?;
     match (a_metadata, b_metadata) {
-        (Some(a_metadata), Some(b_metadata)) => Ok(a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino()),
+        (Some(a_metadata), Some(b_metadata)) => {
+            if a_metadata.len() != b_metadata.len() {
+                return Ok(false);
+            }
+            Ok(hash_file(a)? == hash_file(b)?)
+        },
         (None, None) => Err(io::Error::new(io::ErrorKind::NotFound, "Both files do not exist")),
         _ => Ok(false),
     }
 }
 
+// Stream a file through SHA-256 in fixed-size chunks.
+fn hash_file(path: &Path) -> Result<[u8; 32], io::Error> {
+    use std::io::Read;
+    use sha2::{Sha256, Digest};
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
 pub fn metadata_if_exists(path: impl AsRef<Path>) -> Result<Option<fs::Metadata>, io::Error> {
     match fs::metadata(path) {
         Ok(metadata) => Ok(Some(metadata)),
@@ -130,27 +572,145 @@ pub fn metadata_if_exists(path: impl AsRef<Path>) -> Result<Option<fs::Metadata>
     }
 }
 
+pub fn metadata_if_exists_in(fs: &dyn Fs, path: impl AsRef<Path>) -> Result<Option<Meta>, io::Error> {
+    match fs.metadata(path.as_ref()) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(None),
+            _ => Err(err),
+        }
+    }
+}
+
 pub fn backup(path: impl AsRef<Path>) -> Result<(), io::Error> {
+    backup_with(path, BackupOptions::default())
+}
+
+// Compression applied to a backup copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+}
+
+// Tuning knobs for `backup_with`. The default (both `None`) reproduces the
+// behavior of the bare `backup` helper: an uncompressed, uncapped copy.
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    // Keep at most this many numbered backups, deleting the oldest beyond it.
+    pub max_retained: Option<usize>,
+    // Compress the backup copy instead of copying the bytes verbatim.
+    pub compression: Option<Compression>,
+}
+
+pub fn backup_with(path: impl AsRef<Path>, opts: BackupOptions) -> Result<(), io::Error> {
+    backup_with_in(&RealFs, path, opts)
+}
+
+// Like `backup`, but honoring a retention cap and optional compression.
+//
+// Without a retention cap this keeps the original append-only layout: the
+// newest copy takes the next free index and `.bak` stays the oldest, untouched
+// copy — so the zero-option `backup` wrapper behaves exactly as before. Once a
+// cap is set the chain is rotated up (`.bak` -> `.bak.1` -> ...) so the newest
+// lands at `.bak`, then copies beyond `max_retained` are pruned from the high
+// (oldest) end.
+pub fn backup_with_in(fs: &dyn Fs, path: impl AsRef<Path>, opts: BackupOptions) -> Result<(), io::Error> {
+    use std::ffi::OsStr;
+
     let path = path.as_ref();
     let filename = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid path: {}", path.display())))?;
-    let mut n_retries = 0;
+    let suffix: &OsStr = match opts.compression {
+        Some(Compression::Xz) => OsStr::new(".xz"),
+        None => OsStr::new(""),
+    };
+
+    let backup_path = match opts.max_retained {
+        None => {
+            // Append at the next free slot, leaving existing backups untouched.
+            let mut n = 0;
+            loop {
+                let candidate = numbered_backup_path(path, filename, n, suffix);
+                if metadata_if_exists_in(fs, &candidate)?.is_some() {
+                    n += 1;
+                    continue;
+                }
+                break candidate;
+            }
+        },
+        Some(_) => {
+            // Rotate existing copies up, highest index first so none is clobbered.
+            let mut count = 0;
+            while metadata_if_exists_in(fs, numbered_backup_path(path, filename, count, suffix))?.is_some() {
+                count += 1;
+            }
+            for i in (0..count).rev() {
+                let from = numbered_backup_path(path, filename, i, suffix);
+                let to = numbered_backup_path(path, filename, i + 1, suffix);
+                fs.rename(&from, &to)?;
+            }
+            numbered_backup_path(path, filename, 0, suffix)
+        },
+    };
+
+    write_backup(fs, path, &backup_path, opts.compression)?;
+
+    if let Some(max) = opts.max_retained {
+        prune_backups(fs, path, filename, suffix, max)?;
+    }
+
+    Ok(())
+}
+
+// Copy `src` to `dest`, optionally compressing the bytes on the way. The xz
+// encoder buffers into memory so the same path works over any `Fs`.
+fn write_backup(fs: &dyn Fs, src: &Path, dest: &Path, compression: Option<Compression>) -> Result<(), io::Error> {
+    match compression {
+        Some(Compression::Xz) => {
+            use std::io::Write;
+            let data = fs.read(src)?;
+            // preset 9 selects a large dictionary window, which pays off on the
+            // repetitive text/config files this crate tends to back up.
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 9);
+            encoder.write_all(&data)?;
+            let compressed = encoder.finish()?;
+            fs.write(dest, &compressed)?;
+        },
+        None => {
+            fs.copy(src, dest)?;
+        },
+    }
+    Ok(())
+}
+
+// Build the path of the `n`-th numbered backup: `file.bak[.N][suffix]`.
+fn numbered_backup_path(path: &Path, filename: &std::ffi::OsStr, n: usize, suffix: &std::ffi::OsStr) -> PathBuf {
+    let extension = if n == 0 {
+        "bak".to_string()
+    } else {
+        format!("bak.{}", n)
+    };
+    let mut backup_filename = filename.to_os_string();
+    backup_filename.push(".");
+    backup_filename.push(extension);
+    backup_filename.push(suffix);
+    path.with_file_name(backup_filename)
+}
+
+// Delete the oldest numbered backups so at most `max` remain. After rotation
+// the newest copy is `.bak` and the oldest is the highest numbered one, so
+// everything at or above index `max` is dropped.
+fn prune_backups(fs: &dyn Fs, path: &Path, filename: &std::ffi::OsStr, suffix: &std::ffi::OsStr, max: usize) -> Result<(), io::Error> {
+    let mut n = max;
     loop {
-        let extension = if n_retries == 0 {
-            "bak".to_string()
+        let candidate = numbered_backup_path(path, filename, n, suffix);
+        if metadata_if_exists_in(fs, &candidate)?.is_some() {
+            fs.remove_file(&candidate)?;
+            n += 1;
         } else {
-            format!("bak.{}", n_retries)
-        };
-        let mut backup_filename = filename.to_os_string();
-        backup_filename.push(".");
-        backup_filename.push(extension);
-        let backup_path = path.with_file_name(backup_filename);
-        if backup_path.exists() {
-            n_retries += 1;
-            continue;
+            break;
         }
-        fs::copy(path, &backup_path)?;
-        break Ok(());
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -160,8 +720,8 @@ mod tests {
     #[test]
     fn test_walk_dir() {
         let files = walk_dir("./src", |path| path);
-        assert!(files.len() > 0);
-        assert!(files.iter().find(|path| path.ends_with("lib.rs")).is_some());
+        assert!(!files.is_empty());
+        assert!(files.iter().any(|path| path.ends_with("lib.rs")));
     }
 
     #[test]
@@ -178,14 +738,124 @@ mod tests {
         assert!(!eq_files("./src/../src/lib.rs", "./Cargo.toml").unwrap());
     }
 
+    #[test]
+    fn test_eq_files_by_content() {
+        let a = Path::new("test_eq_content_a");
+        let b = Path::new("test_eq_content_b");
+        let c = Path::new("test_eq_content_c");
+        fs::write(a, "same").unwrap();
+        fs::write(b, "same").unwrap();
+        fs::write(c, "different").unwrap();
+        assert!(eq_files_by_content(a, b).unwrap());
+        assert!(!eq_files_by_content(a, c).unwrap());
+        for path in [a, b, c] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
     #[test]
     fn test_backup() {
         let path = Path::new("test_backup");
-        fs::write(&path, "test").unwrap();
-        backup(&path).unwrap();
+        fs::write(path, "test").unwrap();
+        backup(path).unwrap();
         let backpath = Path::new("test_backup.bak");
-        fs::remove_file(&backpath).unwrap();
-        fs::remove_file(&path).unwrap();
+        fs::remove_file(backpath).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_with_retention() {
+        let path = Path::new("test_backup_retention");
+        let opts = BackupOptions { max_retained: Some(2), compression: None };
+        // Four distinct generations with a cap of two: enough to exercise more
+        // than one prune cycle.
+        for content in ["v1", "v2", "v3", "v4"] {
+            fs::write(path, content).unwrap();
+            backup_with(path, opts.clone()).unwrap();
+        }
+        let bak = path.with_file_name("test_backup_retention.bak");
+        let bak1 = path.with_file_name("test_backup_retention.bak.1");
+        let bak2 = path.with_file_name("test_backup_retention.bak.2");
+        // Newest two generations are retained, oldest are pruned.
+        assert_eq!(fs::read_to_string(&bak).unwrap(), "v4");
+        assert_eq!(fs::read_to_string(&bak1).unwrap(), "v3");
+        assert!(!bak2.exists());
+        fs::remove_file(&bak).unwrap();
+        fs::remove_file(&bak1).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_with_xz_roundtrip() {
+        use std::io::Read;
+        let fs = FakeFs::new();
+        let content = b"config data ".repeat(100);
+        fs.write(Path::new("/c/app.toml"), &content).unwrap();
+        backup_with_in(&fs, "/c/app.toml", BackupOptions { max_retained: None, compression: Some(Compression::Xz) }).unwrap();
+        let compressed = fs.read(Path::new("/c/app.toml.bak.xz")).unwrap();
+        assert!(compressed.len() < content.len(), "xz should shrink repetitive data");
+        let mut decoded = Vec::new();
+        xz2::read::XzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[test]
+    fn test_rename_file_exdev_fallback() {
+        let mut fs = FakeFs::new();
+        fs.exdev_on_rename = true;
+        fs.write(Path::new("/a"), b"hello").unwrap();
+        rename_file_in(&fs, "/a", "/b").unwrap();
+        assert!(metadata_if_exists_in(&fs, "/a").unwrap().is_none());
+        assert_eq!(fs.metadata(Path::new("/b")).unwrap().len, 5);
+    }
+
+    #[test]
+    fn test_backup_retry() {
+        let fs = FakeFs::new();
+        fs.write(Path::new("/dir/config"), b"v1").unwrap();
+        backup_with_in(&fs, "/dir/config", BackupOptions::default()).unwrap();
+        backup_with_in(&fs, "/dir/config", BackupOptions::default()).unwrap();
+        assert!(metadata_if_exists_in(&fs, "/dir/config.bak").unwrap().is_some());
+        assert!(metadata_if_exists_in(&fs, "/dir/config.bak.1").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_walk_dir_filtered_prunes_and_reincludes() {
+        let fs = FakeFs::new();
+        fs.write(Path::new("/root/.gitignore"), b"*.log\n!keep.log\ntarget/\n").unwrap();
+        fs.write(Path::new("/root/main.rs"), b"").unwrap();
+        fs.write(Path::new("/root/debug.log"), b"").unwrap();
+        fs.write(Path::new("/root/keep.log"), b"").unwrap();
+        fs.write(Path::new("/root/target/out.o"), b"").unwrap();
+        let found: Vec<String> = walk_dir_filtered_in(&fs, "/root", Vec::<&str>::new(), |p| p.to_string_lossy().into_owned());
+        assert!(found.iter().any(|p| p.ends_with("main.rs")));
+        assert!(found.iter().any(|p| p.ends_with("keep.log")), "last-match-wins should re-include keep.log");
+        assert!(!found.iter().any(|p| p.ends_with("debug.log")), "*.log should be ignored");
+        assert!(!found.iter().any(|p| p.contains("target")), "ignored dir should be pruned, not descended");
+    }
+
+    #[test]
+    fn test_walk_dir_filtered_dir_only_and_caller_patterns() {
+        let fs = FakeFs::new();
+        fs.write(Path::new("/p/.gitignore"), b"build/\ncache/\n").unwrap();
+        fs.write(Path::new("/p/cache"), b"").unwrap();          // a *file* named like a dir-only rule
+        fs.write(Path::new("/p/build/art.bin"), b"").unwrap();  // a directory matched by `build/`
+        fs.write(Path::new("/p/src/app.rs"), b"").unwrap();
+        let found: Vec<String> = walk_dir_filtered_in(&fs, "/p", ["*.rs"], |p| p.to_string_lossy().into_owned());
+        assert!(found.iter().any(|p| p.ends_with("/cache")), "dir-only rule must not match a file");
+        assert!(!found.iter().any(|p| p.contains("build")), "dir-only rule should prune the directory");
+        assert!(!found.iter().any(|p| p.ends_with("app.rs")), "caller-supplied pattern should exclude *.rs");
+    }
+
+    #[test]
+    fn test_walk_dir_filtered_anchored() {
+        let fs = FakeFs::new();
+        fs.write(Path::new("/a/.gitignore"), b"/note.txt\n").unwrap();
+        fs.write(Path::new("/a/note.txt"), b"").unwrap();
+        fs.write(Path::new("/a/sub/note.txt"), b"").unwrap();
+        let found: Vec<String> = walk_dir_filtered_in(&fs, "/a", Vec::<&str>::new(), |p| p.to_string_lossy().into_owned());
+        assert!(!found.iter().any(|p| p.ends_with("/a/note.txt")), "anchored rule matches only at its base dir");
+        assert!(found.iter().any(|p| p.ends_with("/sub/note.txt")), "anchored rule must not match in subdirs");
     }
 }
 